@@ -1,26 +1,40 @@
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::future::ready;
 use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Error, Result};
 use env_logger::Builder;
 use futures_util::StreamExt;
+use git2::build::RepoBuilder;
+use git2::{Cred, CredentialType, FetchOptions, RemoteCallbacks, Repository};
 use hyper::header;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use hyper_staticfile::Static;
 use log::{error, info};
 use once_cell::race::OnceBox;
-use reqwest::get;
-use serde::Deserialize;
-use tokio::fs::{create_dir_all, read_to_string, remove_file, OpenOptions};
-use tokio::io;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use reqwest::{get, Client};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{
+    create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, write, OpenOptions,
+};
 use tokio::io::copy;
+use tokio::process::Command as TokioCommand;
 use tokio::signal::ctrl_c;
 use tokio::spawn;
+use tokio::task::spawn_blocking;
 use tokio_cron_scheduler::{Job, JobScheduler};
+use uuid::Uuid;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -28,6 +42,7 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 struct Config {
     mirrors: Vec<Mirror>,
     admin_server: Option<AdminServer>,
+    notifications: Option<Vec<Notification>>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -36,6 +51,34 @@ struct Mirror {
     source: String,
     sync: Option<String>,
     serve: Option<String>,
+    source_type: Option<SourceType>,
+    branch: Option<String>,
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    ssh_private_key: Option<String>,
+    ssh_passphrase: Option<String>,
+    upstream: Option<String>,
+    notifications: Option<Vec<Notification>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Notification {
+    webhook: Option<String>,
+    command: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SourceType {
+    Zip,
+    Git,
+    Proxy,
+}
+
+impl Default for SourceType {
+    fn default() -> Self {
+        SourceType::Zip
+    }
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -44,6 +87,128 @@ struct AdminServer {
     token: String,
 }
 
+struct Metrics {
+    registry: Registry,
+    sync_total: IntCounterVec,
+    last_success_timestamp: GaugeVec,
+    last_sync_bytes: GaugeVec,
+    sync_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let sync_total = IntCounterVec::new(
+            Opts::new(
+                "mirror_sync_total",
+                "Total number of sync attempts per mirror",
+            ),
+            &["name", "result"],
+        )?;
+        registry.register(Box::new(sync_total.clone()))?;
+
+        let last_success_timestamp = GaugeVec::new(
+            Opts::new(
+                "mirror_last_success_timestamp",
+                "Unix timestamp of the last successful sync",
+            ),
+            &["name"],
+        )?;
+        registry.register(Box::new(last_success_timestamp.clone()))?;
+
+        let last_sync_bytes = GaugeVec::new(
+            Opts::new(
+                "mirror_last_sync_bytes",
+                "Bytes downloaded during the last sync",
+            ),
+            &["name"],
+        )?;
+        registry.register(Box::new(last_sync_bytes.clone()))?;
+
+        let sync_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "mirror_sync_duration_seconds",
+                "Duration of a mirror sync in seconds",
+            ),
+            &["name"],
+        )?;
+        registry.register(Box::new(sync_duration.clone()))?;
+
+        Ok(Self {
+            registry,
+            sync_total,
+            last_success_timestamp,
+            last_sync_bytes,
+            sync_duration,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "state")]
+enum SyncState {
+    Pending,
+    Running,
+    Succeeded { at: u64, bytes: u64 },
+    Failed { at: u64, error: String },
+}
+
+struct StateStore {
+    path: PathBuf,
+    states: Mutex<HashMap<String, SyncState>>,
+}
+
+impl StateStore {
+    fn load(path: PathBuf) -> Self {
+        let states = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            states: Mutex::new(states),
+        }
+    }
+
+    /// Atomically transitions a mirror to `Running` unless it already is,
+    /// so two syncs of the same mirror - whether from startup, cron, or the
+    /// admin endpoint - can't race.
+    fn try_begin(&self, name: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        if let Some(SyncState::Running) = states.get(name) {
+            return false;
+        }
+        states.insert(name.to_string(), SyncState::Running);
+        drop(states);
+        self.persist();
+        true
+    }
+
+    fn set(&self, name: &str, state: SyncState) {
+        let mut states = self.states.lock().unwrap();
+        states.insert(name.to_string(), state);
+        drop(states);
+        self.persist();
+    }
+
+    fn get(&self, name: &str) -> Option<SyncState> {
+        self.states.lock().unwrap().get(name).cloned()
+    }
+
+    fn all(&self) -> HashMap<String, SyncState> {
+        self.states.lock().unwrap().clone()
+    }
+
+    fn persist(&self) {
+        let states = self.states.lock().unwrap();
+        if let Ok(raw) = serde_json::to_string(&*states) {
+            let _ = std::fs::write(&self.path, raw);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let mut log_builder = Builder::new();
@@ -62,6 +227,8 @@ static GLOBAL_CONFIG: OnceBox<Config> = OnceBox::new();
 static CURRENT_PATH: OnceBox<PathBuf> = OnceBox::new();
 static DATA_PATH: OnceBox<PathBuf> = OnceBox::new();
 static TEMP_PATH: OnceBox<PathBuf> = OnceBox::new();
+static METRICS: OnceBox<Metrics> = OnceBox::new();
+static SYNC_STATE: OnceBox<StateStore> = OnceBox::new();
 
 async fn main_intl() -> Result<()> {
     CURRENT_PATH
@@ -75,6 +242,9 @@ async fn main_intl() -> Result<()> {
     TEMP_PATH
         .set(Box::new(Path::new(&current_path).join("tmp")))
         .unwrap();
+    METRICS.set(Box::new(Metrics::new()?)).unwrap();
+    create_dir_all(data_path).await?;
+    cleanup_stale_dirs().await?;
 
     let config_path = Path::new(&current_path).join("mirror.yml");
     let try_config_raw = read_to_string(config_path).await;
@@ -96,6 +266,14 @@ async fn main_intl() -> Result<()> {
         return Err(Error::msg("No mirror found."));
     }
 
+    let state_store = StateStore::load(Path::new(&data_path).join(".sync-state.json"));
+    for mirror in &config.mirrors {
+        if state_store.get(&mirror.name).is_none() {
+            state_store.set(&mirror.name, SyncState::Pending);
+        }
+    }
+    SYNC_STATE.set(Box::new(state_store)).unwrap();
+
     let scheduler = JobScheduler::new().await?;
 
     for mirror in &config.mirrors {
@@ -121,15 +299,19 @@ async fn main_intl() -> Result<()> {
         if let Some(listen) = &mirror.serve {
             info!("Initializing server {} for {}", &listen, &mirror.name);
 
-            let hyper_static = Static::new(&root_path);
+            let serve_context = ServeContext {
+                hyper_static: Static::new(&root_path),
+                upstream: mirror.upstream.clone(),
+                root_path: root_path.clone(),
+            };
 
             spawn(
                 Server::bind(&SocketAddr::from_str(&*listen)?).serve(make_service_fn(
                     move |_conn| {
-                        let hyper_static = hyper_static.clone();
+                        let serve_context = serve_context.clone();
 
                         ready(Ok::<_, hyper::Error>(service_fn(move |req| {
-                            serve_handler(req, hyper_static.clone())
+                            serve_handler(req, serve_context.clone())
                         })))
                     },
                 )),
@@ -155,11 +337,148 @@ async fn main_intl() -> Result<()> {
     Ok(())
 }
 
-async fn serve_handler<B>(
-    req: Request<B>,
+#[derive(Clone)]
+struct ServeContext {
     hyper_static: Static,
-) -> Result<Response<Body>, io::Error> {
-    hyper_static.serve(req).await
+    upstream: Option<String>,
+    root_path: PathBuf,
+}
+
+async fn serve_handler<B>(req: Request<B>, ctx: ServeContext) -> Result<Response<Body>, Error> {
+    let request_path = req.uri().path().to_string();
+
+    let response = ctx.hyper_static.serve(req).await?;
+    if response.status() != StatusCode::NOT_FOUND {
+        return Ok(response);
+    }
+
+    let upstream = match &ctx.upstream {
+        Some(upstream) => upstream,
+        None => return Ok(response),
+    };
+
+    let relative_path = match safe_relative_path(&request_path) {
+        Some(path) => path,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("[MIRROR] invalid path".into())?);
+        }
+    };
+
+    fetch_and_cache(upstream, &relative_path, &ctx.root_path).await
+}
+
+/// Turns a request path into a path relative to a mirror root, rejecting
+/// `..`/root/prefix components so it can never escape `root_path` when
+/// joined onto it. Returns `None` for anything that doesn't resolve to a
+/// plain descendant path.
+fn safe_relative_path(request_path: &str) -> Option<PathBuf> {
+    let mut relative_path = PathBuf::new();
+    for component in Path::new(request_path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => relative_path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if relative_path.as_os_str().is_empty() {
+        return None;
+    }
+    Some(relative_path)
+}
+
+async fn fetch_and_cache(
+    upstream: &str,
+    relative_path: &Path,
+    root_path: &Path,
+) -> Result<Response<Body>, Error> {
+    let upstream_url = format!(
+        "{}/{}",
+        upstream.trim_end_matches('/'),
+        relative_path.to_string_lossy()
+    );
+
+    let upstream_response = get(&upstream_url).await?;
+    if upstream_response.status() != StatusCode::OK {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("[MIRROR] not found".into())?);
+    }
+
+    let content_type = upstream_response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .cloned();
+
+    let cache_path = Path::new(root_path).join(relative_path);
+    if let Some(parent) = cache_path.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    // Write to a private temp file and only rename it into place once the
+    // whole body has arrived successfully, so a disconnected client or a
+    // mid-stream upstream error can never leave a truncated file sitting at
+    // the live cache path to be served as a complete 200 afterwards. Each
+    // request gets its own temp file, so two concurrent misses for the same
+    // path write independently instead of interleaving into one file.
+    let temp_path = cache_path.with_file_name(format!(
+        ".{}.tmp-{}",
+        cache_path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default(),
+        Uuid::new_v4()
+    ));
+    let mut cache_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .await?;
+
+    // Tee each chunk to the temp file and the client as it arrives,
+    // instead of buffering the whole response in memory.
+    let (mut sender, body) = Body::channel();
+    spawn(async move {
+        let mut response_data = upstream_response.bytes_stream();
+        loop {
+            let chunk = match response_data.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => {
+                    error!("Upstream stream error: {e}");
+                    sender.abort();
+                    let _ = remove_file(&temp_path).await;
+                    return;
+                }
+                None => break,
+            };
+
+            if let Err(e) = copy(&mut chunk.as_ref(), &mut cache_file).await {
+                error!("Failed to write cache file: {e}");
+                sender.abort();
+                let _ = remove_file(&temp_path).await;
+                return;
+            }
+
+            if sender.send_data(chunk).await.is_err() {
+                let _ = remove_file(&temp_path).await;
+                return;
+            }
+        }
+
+        if let Err(e) = tokio::fs::rename(&temp_path, &cache_path).await {
+            error!("Failed to promote cache file: {e}");
+            let _ = remove_file(&temp_path).await;
+        }
+    });
+
+    let mut builder = Response::builder().status(StatusCode::OK);
+    if let Some(content_type) = content_type {
+        builder = builder.header(header::CONTENT_TYPE, content_type);
+    }
+
+    Ok(builder.body(body)?)
 }
 
 async fn admin_handler<B>(req: Request<B>) -> Result<Response<Body>, Error> {
@@ -186,6 +505,20 @@ async fn admin_handler<B>(req: Request<B>) -> Result<Response<Body>, Error> {
         }
     }
 
+    if req.method() == Method::GET && req.uri().path() == "/metrics" {
+        return render_metrics();
+    }
+
+    if req.method() == Method::GET && req.uri().path() == "/status" {
+        return render_status(None);
+    }
+
+    if req.method() == Method::GET {
+        if let Some(name) = req.uri().path().strip_prefix("/status/") {
+            return render_status(Some(name));
+        }
+    }
+
     if req.method() != Method::POST {
         return Ok(Response::builder()
             .status(StatusCode::METHOD_NOT_ALLOWED)
@@ -208,10 +541,13 @@ async fn admin_handler<B>(req: Request<B>) -> Result<Response<Body>, Error> {
     let name = name.unwrap();
 
     match try_sync_by_name(name).await {
-        None => Ok(Response::builder()
+        TrySyncOutcome::NotFound => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body("[MIRROR] not found".into())?),
-        Some(_) => {
+        TrySyncOutcome::Conflict => Ok(Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body("[MIRROR] sync already running".into())?),
+        TrySyncOutcome::Started => {
             info!("Sync for {name} started as request");
 
             Ok(Response::builder()
@@ -221,7 +557,52 @@ async fn admin_handler<B>(req: Request<B>) -> Result<Response<Body>, Error> {
     }
 }
 
+fn render_status(name: Option<&str>) -> Result<Response<Body>, Error> {
+    let state_store = SYNC_STATE.get().unwrap();
+
+    let body = match name {
+        None => serde_json::to_string(&state_store.all())?,
+        Some(name) => match state_store.get(name) {
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body("[MIRROR] not found".into())?);
+            }
+            Some(state) => serde_json::to_string(&state)?,
+        },
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))?)
+}
+
+fn render_metrics() -> Result<Response<Body>, Error> {
+    let metrics = METRICS.get().unwrap();
+
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder.encode(&metrics.registry.gather(), &mut buffer)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))?)
+}
+
+/// Single entry point for starting a sync, used by the startup pass, the
+/// cron job, and the admin-triggered path alike, so `try_begin` is the one
+/// place that decides whether a mirror may start syncing. This is what
+/// actually keeps two overlapping syncs of the same mirror from running
+/// concurrently; `try_sync_by_name`'s own check is only a best-effort early
+/// reply to the HTTP caller.
 async fn sync(mirror: &Mirror) {
+    if !SYNC_STATE.get().unwrap().try_begin(&mirror.name) {
+        info!("Sync for {} is already running, skipping", mirror.name);
+        return;
+    }
+
     if let Err(e) = sync_intl(mirror).await {
         error!("Error when syncing {}", mirror.name);
         error!("{e}");
@@ -230,12 +611,98 @@ async fn sync(mirror: &Mirror) {
 }
 
 async fn sync_intl(mirror: &Mirror) -> Result<()> {
+    let started_at = Instant::now();
+
+    let result = match mirror.source_type.clone().unwrap_or_default() {
+        SourceType::Zip => sync_zip(mirror).await,
+        SourceType::Git => sync_git(mirror).await,
+        SourceType::Proxy => {
+            info!("{} is a lazy-proxy mirror, nothing to sync", mirror.name);
+            Ok(0)
+        }
+    };
+
+    let duration = started_at.elapsed();
+    let metrics = METRICS.get().unwrap();
+    metrics
+        .sync_duration
+        .with_label_values(&[&mirror.name])
+        .observe(duration.as_secs_f64());
+
+    let state_store = SYNC_STATE.get().unwrap();
+    match &result {
+        Ok(bytes) => {
+            metrics
+                .sync_total
+                .with_label_values(&[&mirror.name, "success"])
+                .inc();
+            metrics
+                .last_success_timestamp
+                .with_label_values(&[&mirror.name])
+                .set(unix_now() as f64);
+            metrics
+                .last_sync_bytes
+                .with_label_values(&[&mirror.name])
+                .set(*bytes as f64);
+            state_store.set(
+                &mirror.name,
+                SyncState::Succeeded {
+                    at: unix_now(),
+                    bytes: *bytes,
+                },
+            );
+        }
+        Err(e) => {
+            metrics
+                .sync_total
+                .with_label_values(&[&mirror.name, "failure"])
+                .inc();
+            state_store.set(
+                &mirror.name,
+                SyncState::Failed {
+                    at: unix_now(),
+                    error: e.to_string(),
+                },
+            );
+        }
+    }
+
+    notify(mirror, &result, duration).await;
+
+    result.map(|_| ())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn sync_zip(mirror: &Mirror) -> Result<u64> {
     let filename = mirror.name.clone() + ".zip";
     let temp_path = TEMP_PATH.get().unwrap();
     create_dir_all(&temp_path).await?;
     let filepath = Path::new(temp_path).join(&filename);
 
-    let response = get(&mirror.source).await?;
+    let data_path = DATA_PATH.get().unwrap();
+    let root_path = Path::new(&data_path).join(&mirror.name);
+    let meta_path = root_path.join(".mirror-meta.json");
+    let meta = read_mirror_meta(&meta_path).await;
+
+    let mut request = Client::new().get(&mirror.source);
+    if let Some(etag) = &meta.etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &meta.last_modified {
+        request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        info!("{} is unchanged, skipping sync", mirror.name);
+        return Ok(0);
+    }
 
     if response.status() != StatusCode::OK {
         return Err(Error::msg(format!(
@@ -245,6 +712,11 @@ async fn sync_intl(mirror: &Mirror) -> Result<()> {
         )));
     }
 
+    let new_meta = MirrorMeta {
+        etag: header_value(&response, header::ETAG),
+        last_modified: header_value(&response, header::LAST_MODIFIED),
+    };
+
     let _ = remove_file(&filepath).await;
 
     let mut async_file = OpenOptions::new()
@@ -254,36 +726,310 @@ async fn sync_intl(mirror: &Mirror) -> Result<()> {
         .open(&filepath)
         .await?;
 
+    let mut bytes_downloaded = 0u64;
     let mut response_data = response.bytes_stream();
     while let Some(i) = response_data.next().await {
-        copy(&mut i?.as_ref(), &mut async_file).await?;
+        let chunk = i?;
+        bytes_downloaded += chunk.len() as u64;
+        copy(&mut chunk.as_ref(), &mut async_file).await?;
     }
 
-    let data_path = DATA_PATH.get().unwrap();
-    let root_path = Path::new(&data_path).join(&mirror.name);
+    // Stage next to the live mirror dir (not under `temp_path`) so the
+    // final promote is a same-filesystem rename: `temp_path` and `data_path`
+    // are commonly separate mounts, and a cross-filesystem rename fails
+    // with EXDEV instead of being atomic.
+    let staging_path =
+        Path::new(data_path).join(format!(".staging-{}-{}", mirror.name, Uuid::new_v4()));
 
     let file = async_file.into_std().await;
-    unzip(file, root_path)?;
+    unzip(file, staging_path.clone())?;
+
+    promote_dir(&root_path, &staging_path).await?;
+    write_mirror_meta(&meta_path, &new_meta).await?;
 
     remove_file(filepath).await?;
 
+    Ok(bytes_downloaded)
+}
+
+fn header_value(response: &reqwest::Response, name: header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+struct MirrorMeta {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+async fn read_mirror_meta(meta_path: &Path) -> MirrorMeta {
+    match read_to_string(meta_path).await {
+        Ok(raw) => serde_json::from_str(&raw).unwrap_or_default(),
+        Err(_) => MirrorMeta::default(),
+    }
+}
+
+async fn write_mirror_meta(meta_path: &Path, meta: &MirrorMeta) -> Result<()> {
+    write(meta_path, serde_json::to_string(meta)?).await?;
     Ok(())
 }
 
-async fn try_sync_by_name(name: &str) -> Option<()> {
+async fn sync_git(mirror: &Mirror) -> Result<u64> {
+    let data_path = DATA_PATH.get().unwrap();
+    let root_path = Path::new(&data_path).join(&mirror.name);
+
+    let mirror = mirror.clone();
+    spawn_blocking(move || git_sync(root_path, mirror)).await?
+}
+
+fn git_sync(root_path: PathBuf, mirror: Mirror) -> Result<u64> {
+    // Use sync operations instead of tokio only in this fn
+    let received_bytes = Rc::new(Cell::new(0u64));
+    let refspec = mirror
+        .git_ref
+        .clone()
+        .or_else(|| mirror.branch.clone())
+        .unwrap_or_else(|| "HEAD".to_string());
+
+    if root_path.join(".git").is_dir() {
+        let repo = Repository::open(&root_path)?;
+        let mut remote = repo.find_remote("origin")?;
+
+        remote.fetch(
+            &[&refspec],
+            Some(&mut fetch_options(received_bytes.clone(), &mirror)),
+            None,
+        )?;
+
+        let fetch_head = repo.find_reference("FETCH_HEAD")?;
+        let target = repo.reference_to_annotated_commit(&fetch_head)?;
+        let commit = repo.find_commit(target.id())?;
+
+        repo.reset(commit.as_object(), git2::ResetType::Hard, None)?;
+    } else {
+        let _ = std::fs::remove_dir_all(&root_path);
+
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(fetch_options(received_bytes.clone(), &mirror));
+        if let Some(branch) = &mirror.branch {
+            builder.branch(branch);
+        }
+        let repo = builder.clone(&mirror.source, &root_path)?;
+
+        if mirror.branch.is_none() {
+            if let Some(git_ref) = &mirror.git_ref {
+                let object = repo.revparse_single(git_ref)?;
+                repo.checkout_tree(&object, None)?;
+                repo.set_head_detached(object.id())?;
+            }
+        }
+    }
+
+    Ok(received_bytes.get())
+}
+
+fn fetch_options<'a>(received_bytes: Rc<Cell<u64>>, mirror: &Mirror) -> FetchOptions<'a> {
+    let ssh_private_key = mirror.ssh_private_key.clone();
+    let ssh_passphrase = mirror.ssh_passphrase.clone();
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |_url, username_from_url, allowed_types| {
+        let username = username_from_url.unwrap_or("git");
+
+        // libgit2 probes the callback once per allowed credential type: for
+        // an `ssh://` URL with no embedded user it first asks for a
+        // username, then comes back asking for the SSH key. Returning an
+        // SSH cred for the username probe (or an SSH cred for an
+        // HTTPS-authenticated source) just fails auth, so match on what's
+        // actually being asked for.
+        if allowed_types.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+
+        if !allowed_types.contains(CredentialType::SSH_KEY) {
+            return Err(git2::Error::from_str(
+                "mirror has no credentials for the requested authentication type",
+            ));
+        }
+
+        match &ssh_private_key {
+            Some(key_path) => Cred::ssh_key(
+                username,
+                None,
+                Path::new(key_path),
+                ssh_passphrase.as_deref(),
+            ),
+            None => Cred::ssh_key_from_agent(username),
+        }
+    });
+    callbacks.transfer_progress(move |stats| {
+        received_bytes.set(stats.received_bytes() as u64);
+        true
+    });
+
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+    fetch_options
+}
+
+/// Atomically promote a fully-written staging directory to be the live
+/// mirror directory, so readers never observe a partially-written sync.
+async fn promote_dir(live_path: &Path, staging_path: &Path) -> Result<()> {
+    // Append rather than use `with_extension`, which would replace an
+    // existing extension and let mirrors sharing a stem (e.g. `repo.git`
+    // and `repo.zip`) collide on the same `.old` path.
+    let old_file_name = match live_path.file_name().and_then(|name| name.to_str()) {
+        Some(file_name) => format!("{file_name}.old"),
+        None => return Err(Error::msg("Mirror path has no file name")),
+    };
+    let old_path = live_path.with_file_name(old_file_name);
+    let _ = remove_dir_all(&old_path).await;
+
+    if live_path.exists() {
+        tokio::fs::rename(live_path, &old_path).await?;
+    }
+    tokio::fs::rename(staging_path, live_path).await?;
+
+    let _ = remove_dir_all(&old_path).await;
+
+    Ok(())
+}
+
+/// Remove leftover staging/`.old` directories from a sync that crashed
+/// before it could finish promoting or cleaning up.
+async fn cleanup_stale_dirs() -> Result<()> {
+    let data_path = DATA_PATH.get().unwrap();
+    let mut entries = read_dir(data_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let is_old = entry.path().extension().and_then(|e| e.to_str()) == Some("old");
+        let is_staging = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(".staging-"));
+        if is_old || is_staging {
+            info!("Removing stale directory {}", entry.path().display());
+            let _ = remove_dir_all(entry.path()).await;
+        }
+    }
+
+    let temp_path = TEMP_PATH.get().unwrap();
+    let _ = create_dir_all(temp_path).await;
+    let mut entries = read_dir(temp_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            info!("Removing stale directory {}", entry.path().display());
+            let _ = remove_dir_all(entry.path()).await;
+        }
+    }
+
+    Ok(())
+}
+
+enum TrySyncOutcome {
+    NotFound,
+    Conflict,
+    Started,
+}
+
+async fn try_sync_by_name(name: &str) -> TrySyncOutcome {
     let option_mirror = GLOBAL_CONFIG
         .get()
         .unwrap()
         .mirrors
         .iter()
         .find(|&x| x.name == name);
-    if let None = option_mirror {
-        return None;
+    let mirror = match option_mirror {
+        None => return TrySyncOutcome::NotFound,
+        Some(mirror) => mirror,
+    };
+
+    // Best-effort early reply only; `sync` itself is what actually gates
+    // overlapping syncs of the same mirror via `try_begin`.
+    if let Some(SyncState::Running) = SYNC_STATE.get().unwrap().get(&mirror.name) {
+        return TrySyncOutcome::Conflict;
     }
 
-    spawn(sync(option_mirror.unwrap()));
+    spawn(sync(mirror));
 
-    Some(())
+    TrySyncOutcome::Started
+}
+
+/// Fires each configured notification target for a finished sync. Best
+/// effort: a webhook that cannot be sent or a command that cannot be spawned
+/// is logged but never fails the sync. The webhook's response status and the
+/// command's exit status are not checked.
+async fn notify(mirror: &Mirror, result: &Result<u64>, duration: Duration) {
+    let config = GLOBAL_CONFIG.get().unwrap();
+    let targets = mirror
+        .notifications
+        .clone()
+        .or_else(|| config.notifications.clone())
+        .unwrap_or_default();
+
+    if targets.is_empty() {
+        return;
+    }
+
+    let (payload, env) = match result {
+        Ok(bytes) => (
+            serde_json::json!({
+                "name": mirror.name,
+                "result": "success",
+                "bytes": bytes,
+                "duration_ms": duration.as_millis() as u64,
+            }),
+            vec![
+                ("MIRROR_NAME".to_string(), mirror.name.clone()),
+                ("MIRROR_RESULT".to_string(), "success".to_string()),
+                ("MIRROR_BYTES".to_string(), bytes.to_string()),
+                (
+                    "MIRROR_DURATION_MS".to_string(),
+                    duration.as_millis().to_string(),
+                ),
+            ],
+        ),
+        Err(e) => (
+            serde_json::json!({
+                "name": mirror.name,
+                "result": "failure",
+                "error": e.to_string(),
+            }),
+            vec![
+                ("MIRROR_NAME".to_string(), mirror.name.clone()),
+                ("MIRROR_RESULT".to_string(), "failure".to_string()),
+                ("MIRROR_ERROR".to_string(), e.to_string()),
+            ],
+        ),
+    };
+
+    for target in &targets {
+        if let Some(webhook) = &target.webhook {
+            if let Err(e) = Client::new().post(webhook).json(&payload).send().await {
+                error!(
+                    "Failed to send webhook notification for {}: {e}",
+                    mirror.name
+                );
+            }
+        }
+
+        if let Some(command) = &target.command {
+            if let Err(e) = TokioCommand::new("sh")
+                .arg("-c")
+                .arg(command)
+                .envs(env.clone())
+                .spawn()
+            {
+                error!(
+                    "Failed to spawn notification command for {}: {e}",
+                    mirror.name
+                );
+            }
+        }
+    }
 }
 
 fn unzip(file: std::fs::File, base_path: PathBuf) -> Result<()> {